@@ -39,8 +39,9 @@ fn try_main(handle: Handle) -> Box<Future<Item = (), Error = ()>> {
         strategy: ShardingStrategy::multi(4),
         token: Rc::new(token),
         ws_uri: Rc::new(String::from("nothing")),
+        config_fn: Rc::new(|_shard_id, builder| builder.build()),
         queue: SimpleReconnectQueue::new(4),
-    }; 
+    };
 
     let mut shard_manager = ShardManager::new(opts, handle.clone());
     let future = shard_manager.start()
@@ -48,31 +49,25 @@ fn try_main(handle: Handle) -> Box<Future<Item = (), Error = ()>> {
 
     handle.spawn(future);
 
-    let future = shard_manager.messages().for_each(move |(shard, message)| {
-        let mut shard = shard.borrow_mut();
-        
-        let event = shard.parse(message)
-            .expect("Could not parse shard stream message");
+    let future = shard_manager.event_stream()
+        .map_err(|e| println!("Error processing shard event stream: {:?}", e))
+        .for_each(move |guard| {
+            match guard.event() {
+                GatewayEvent::Dispatch(_, Event::MessageCreate(ev)) => {
+                    if ev.message.content == "!ping" {
+                        println!("Pong!");
+                    }
+                },
+                GatewayEvent::Dispatch(_, Event::Ready(_)) => {
+                    println!("Connected to Discord!");
+                },
+                _ => {
+                    // Ignore all other messages.
+                },
+            }
 
-        shard.process(&event);
-        shard_manager.process(&event);
-
-        match event {
-            GatewayEvent::Dispatch(_, Event::MessageCreate(ev)) => {
-                if ev.message.content == "!ping" {
-                    println!("Pong!");
-                }
-            },
-            GatewayEvent::Dispatch(_, Event::Ready(_)) => {
-                println!("Connected to Discord!");
-            },
-            _ => {
-                // Ignore all other messages.
-            },
-        }
-
-        future::ok(())
-    });
+            future::ok(())
+        });
 
     Box::new(future)
 }
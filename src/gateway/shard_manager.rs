@@ -1,18 +1,23 @@
-use futures::{future, Future, Stream, Poll, Sink, StartSend, AsyncSink};
+use futures::{future, Async, Future, Stream, Poll, Sink, StartSend, AsyncSink};
+use futures::future::Either;
+use futures::stream::FuturesUnordered;
 use ::Error;
-use std::collections::{VecDeque, HashMap};
+use http;
+use std::collections::{VecDeque, HashMap, HashSet};
 use std::rc::Rc;
-use std::cell::RefCell;
-use std::time::Duration;
-use gateway::shard::Shard;
+use std::cell::{RefCell, RefMut};
+use std::time::{Duration, Instant};
+use gateway::shard::{Shard, ShardConfig, ShardConfigBuilder};
 use model::event::{Event, GatewayEvent};
+use model::gateway::BotGateway;
 use tokio_core::reactor::Handle;
 use tokio_timer::Timer;
 use futures::sync::mpsc::{
-    unbounded, UnboundedSender, UnboundedReceiver, 
+    unbounded, UnboundedSender, UnboundedReceiver,
     channel, Sender as MpscSender, Receiver as MpscReceiver,
     SendError,
 };
+use futures::sync::oneshot::{self, Sender as OneshotSender};
 use tungstenite::{Message as TungsteniteMessage, Error as TungsteniteError};
 
 #[derive(Clone, Copy, Debug)]
@@ -45,24 +50,151 @@ impl Default for ShardingStrategy {
     }
 }
 
+pub type ShardId = u64;
+
+/// Invoked once per shard id, before that shard is created, to produce its
+/// [`ShardConfig`]. Receives a blank [`ShardConfigBuilder`] — there are no
+/// manager-level defaults to seed it with, so callers must set everything
+/// they need (intents, initial presence, large-threshold, compression)
+/// themselves, the same way for every shard id unless they branch on it.
+pub type ConfigFn = Rc<dyn Fn(ShardId, ShardConfigBuilder) -> ShardConfig>;
+
+/// A resumable session handed off by a shard when it disconnects: enough to
+/// send an Opcode 6 RESUME instead of a fresh IDENTIFY.
+#[derive(Clone, Debug)]
+pub struct ResumeSession {
+    pub session_id: String,
+    pub seq: u64,
+    pub resume_gateway_url: String,
+}
+
+/// Stores the last resumable session per shard so `start_shard` can RESUME
+/// instead of IDENTIFY after a transient disconnect. Cloning shares the
+/// same underlying storage, so this can be cheaply handed to every shard's
+/// start task.
 #[derive(Clone, Debug, Default)]
+pub struct SimpleReconnectQueue {
+    sessions: Rc<RefCell<HashMap<ShardId, ResumeSession>>>,
+    /// Shard ids explicitly told by Discord not to resume via
+    /// [`invalidate`], so a late `store` from a shard that was already
+    /// mid-disconnect when the invalidate landed can't resurrect the very
+    /// session that was just thrown away. Cleared by [`take`] once a new
+    /// connection attempt for that shard id actually begins.
+    ///
+    /// [`invalidate`]: #method.invalidate
+    /// [`take`]: #method.take
+    invalidated: Rc<RefCell<HashSet<ShardId>>>,
+}
+
+impl SimpleReconnectQueue {
+    pub fn new(shards_total: u64) -> Self {
+        Self {
+            sessions: Rc::new(RefCell::new(HashMap::with_capacity(shards_total as usize))),
+            invalidated: Rc::new(RefCell::new(HashSet::new())),
+        }
+    }
+
+    /// Records a resumable session for `shard_id`, overwriting any prior
+    /// one — unless `shard_id` was [`invalidate`]d since its last [`take`],
+    /// in which case the store is dropped so a session Discord already
+    /// rejected can't be resurrected by a late disconnect.
+    ///
+    /// [`invalidate`]: #method.invalidate
+    /// [`take`]: #method.take
+    pub fn store(&self, shard_id: ShardId, session: ResumeSession) {
+        if self.invalidated.borrow().contains(&shard_id) {
+            trace!(
+                "not storing resume session for shard {}; it was invalidated since its last start",
+                shard_id,
+            );
+
+            return;
+        }
+
+        self.sessions.borrow_mut().insert(shard_id, session);
+    }
+
+    /// Removes and returns the resumable session for `shard_id`, if any, and
+    /// clears its invalidated marker, since a new connection attempt for it
+    /// is about to begin.
+    pub fn take(&self, shard_id: ShardId) -> Option<ResumeSession> {
+        self.invalidated.borrow_mut().remove(&shard_id);
+        self.sessions.borrow_mut().remove(&shard_id)
+    }
+
+    /// Drops any stored session for `shard_id`, forcing the next start to
+    /// IDENTIFY rather than RESUME, and blocks it from being re-stored until
+    /// the next [`take`].
+    ///
+    /// [`take`]: #method.take
+    pub fn invalidate(&self, shard_id: ShardId) {
+        self.sessions.borrow_mut().remove(&shard_id);
+        self.invalidated.borrow_mut().insert(shard_id);
+    }
+}
+
+#[derive(Clone)]
 pub struct ShardManagerOptions {
     pub strategy: ShardingStrategy,
     pub token: Rc<String>,
     pub ws_uri: Rc<String>,
+    pub config_fn: ConfigFn,
+    pub queue: SimpleReconnectQueue,
+}
+
+impl ::std::fmt::Debug for ShardManagerOptions {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("ShardManagerOptions")
+            .field("strategy", &self.strategy)
+            .field("token", &self.token)
+            .field("ws_uri", &self.ws_uri)
+            .field("config_fn", &"Fn(ShardId, ShardConfigBuilder) -> ShardConfig")
+            .field("queue", &self.queue)
+            .finish()
+    }
+}
+
+impl Default for ShardManagerOptions {
+    fn default() -> Self {
+        Self {
+            strategy: ShardingStrategy::default(),
+            token: Rc::new(String::new()),
+            ws_uri: Rc::new(String::new()),
+            config_fn: Rc::new(|_shard_id, builder| builder.build()),
+            queue: SimpleReconnectQueue::default(),
+        }
+    }
 }
 
 pub type WrappedShard = Rc<RefCell<Shard>>;
 pub type Message = (WrappedShard, TungsteniteMessage);
 pub type MessageStream = UnboundedReceiver<Message>;
 type ShardsMap = Rc<RefCell<HashMap<u64, WrappedShard>>>;
+/// Per-shard shutdown signal, sent by [`ShardManager::shutdown`] and
+/// [`ShardManager::restart`] to tell that shard's forwarding task to close
+/// its websocket and stop.
+type ControlsMap = Rc<RefCell<HashMap<u64, OneshotSender<()>>>>;
+/// The pending shard-id start queue, shared so both [`ShardManager::process`]
+/// and [`ShardEventStream`] can advance it when a shard comes up.
+type StartQueue = Rc<RefCell<VecDeque<u64>>>;
+/// Shard ids whose [`ShardManager::restart`] is waiting on the old shard's
+/// forwarding task to actually finish closing before requeuing them.
+type PendingRestarts = Rc<RefCell<HashSet<u64>>>;
 
 pub struct ShardManager {
-    pub queue: VecDeque<u64>,
+    pub queue: StartQueue,
     shards: ShardsMap,
     pub strategy: ShardingStrategy,
     pub token: Rc<String>,
     pub ws_uri: Rc<String>,
+    /// The number of identify requests Discord will let us make concurrently,
+    /// one per rate-limit bucket. Resolved from `GET /gateway/bot` when
+    /// [`ShardingStrategy::Autoshard`] is used; otherwise left at `1`.
+    pub max_concurrency: u64,
+    config_fn: ConfigFn,
+    controls: ControlsMap,
+    reconnect_queue: SimpleReconnectQueue,
+    pending_restarts: PendingRestarts,
     handle: Handle,
     message_stream: Option<MessageStream>,
     queue_sender: MpscSender<u64>,
@@ -77,11 +209,16 @@ impl ShardManager {
         let (queue_sender, queue_receiver) = channel(10);
 
         Self {
-            queue: VecDeque::new(),
+            queue: Rc::new(RefCell::new(VecDeque::new())),
             shards: Rc::new(RefCell::new(HashMap::new())),
             strategy: options.strategy,
             token: options.token,
             ws_uri: options.ws_uri,
+            max_concurrency: 1,
+            config_fn: options.config_fn,
+            controls: Rc::new(RefCell::new(HashMap::new())),
+            reconnect_queue: options.queue,
+            pending_restarts: Rc::new(RefCell::new(HashSet::new())),
             handle,
             message_stream: None,
             queue_sender,
@@ -92,11 +229,32 @@ impl ShardManager {
 
     pub fn start(&mut self) -> Box<Future<Item = (), Error = Error>> {
         let (
-            shards_index, 
-            shards_count, 
+            shards_index,
+            shards_count,
             shards_total
         ) = match self.strategy {
-            ShardingStrategy::Autoshard => unimplemented!(),
+            ShardingStrategy::Autoshard => {
+                let gateway: BotGateway = match http::get_bot_gateway() {
+                    Ok(gateway) => gateway,
+                    Err(e) => return Box::new(future::err(Error::from(e))),
+                };
+
+                if gateway.session_start_limit.remaining == 0 {
+                    error!(
+                        "session start limit exhausted; resets in {}ms",
+                        gateway.session_start_limit.reset_after,
+                    );
+
+                    return Box::new(future::err(Error::Other(
+                        "session start limit exhausted; cannot autoshard",
+                    )));
+                }
+
+                self.ws_uri = Rc::new(gateway.url);
+                self.max_concurrency = gateway.session_start_limit.max_concurrency;
+
+                (0, gateway.shards, gateway.shards)
+            },
             ShardingStrategy::Range(i, c, t) => (i, c, t),
         };
 
@@ -105,35 +263,28 @@ impl ShardManager {
 
         for shard_id in shards_index..shards_count {
             trace!("pushing shard id {} to back of queue", &shard_id);
-            self.queue.push_back(shard_id);
+            self.queue.borrow_mut().push_back(shard_id);
         }
 
-        let first_shard_id = self.queue.pop_front()
+        let first_shard_id = self.queue.borrow_mut().pop_front()
             .expect("shard start queue is empty");
         
         let token = self.token.clone();
-        let shards_map = self.shards.clone();
         let handle = self.handle.clone();
 
-        /*let future = start_shard(
-            token.clone(),
-            first_shard_id,
-            shards_total,
-            handle.clone(),
-            sender.clone(),
-        ).map(move |shard| {
-            shards_map.borrow_mut().insert(first_shard_id, shard);
-        });*/
-
-        //self.handle.spawn(future);
-
         let future = process_queue(
             self.queue_receiver.take().unwrap(),
             token.clone(),
             shards_total,
+            self.max_concurrency,
+            self.config_fn.clone(),
             handle.clone(),
             sender.clone(),
             self.shards.clone(),
+            self.controls.clone(),
+            self.reconnect_queue.clone(),
+            self.queue_sender.clone(),
+            self.pending_restarts.clone(),
         );
 
         self.queue_sender.try_send(first_shard_id).expect("could not send first shard to start");
@@ -144,12 +295,109 @@ impl ShardManager {
     }
 
     pub fn messages(&mut self) -> MessageStream {
-        self.message_stream.take().unwrap() 
+        self.message_stream.take().unwrap()
     }
 
-    pub fn process(&mut self, event: &GatewayEvent) {
-        if let GatewayEvent::Dispatch(_, Event::Ready(event)) = event {
-            let shard_id = match &event.ready.shard {
+    /// Like [`messages`], but already parses and processes every message and
+    /// yields it through a [`ShardEventStream`], sparing callers the
+    /// `borrow_mut`/`parse`/`process` dance on every iteration.
+    ///
+    /// [`messages`]: #method.messages
+    ///
+    /// Every yielded event has already been run through [`process`], so
+    /// callers don't need to call it themselves.
+    ///
+    /// [`process`]: #method.process
+    pub fn event_stream(&mut self) -> ShardEventStream {
+        ShardEventStream::new(
+            self.messages(),
+            self.queue.clone(),
+            self.queue_sender.clone(),
+            self.reconnect_queue.clone(),
+        )
+    }
+
+    /// `shard_id` is the id of the shard `event` was received on; callers
+    /// get it from the shard itself (e.g. `shard.id()`) since the gateway
+    /// payloads for some events, like `InvalidateSession`, don't carry one.
+    pub fn process(&mut self, shard_id: ShardId, event: &GatewayEvent) {
+        advance_shard_queue(&self.queue, &self.queue_sender, &self.reconnect_queue, shard_id, event);
+    }
+
+    /// Signals every managed shard to close its websocket with a proper
+    /// gateway close frame and stop. Each shard's forwarding task removes
+    /// itself from `shards` once it has finished closing; this only
+    /// broadcasts the signal and clears the pending start queue. The
+    /// returned future resolves immediately and does not wait for any shard
+    /// to finish closing, and the `messages()` stream does not end after a
+    /// shutdown.
+    pub fn shutdown(&mut self) -> Box<Future<Item = (), Error = ()>> {
+        for (shard_id, stop_tx) in self.controls.borrow_mut().drain() {
+            trace!("sending shutdown signal to shard {}", &shard_id);
+
+            if stop_tx.send(()).is_err() {
+                error!("shard {} was already gone when shutting down", &shard_id);
+            }
+        }
+
+        self.queue.borrow_mut().clear();
+
+        Box::new(future::ok(()))
+    }
+
+    /// Stops a single shard and, once its forwarding task has confirmed the
+    /// old websocket actually finished closing, pushes its id back through
+    /// the normal start queue. The old shard's session is stored on close, so
+    /// the new connection RESUMEs it without the identify bucket delay when
+    /// one was available, and otherwise sends a fresh, bucketed IDENTIFY.
+    /// Requeuing is deferred rather than immediate so the new
+    /// connection for `shard_id` can't start while the old one is still
+    /// mid-close.
+    pub fn restart(&mut self, shard_id: u64) {
+        self.shards.borrow_mut().remove(&shard_id);
+
+        match self.controls.borrow_mut().remove(&shard_id) {
+            Some(stop_tx) => {
+                trace!("sending restart signal to shard {}", &shard_id);
+                self.pending_restarts.borrow_mut().insert(shard_id);
+
+                if stop_tx.send(()).is_err() {
+                    error!("shard {} was already gone when restarting", &shard_id);
+                    self.pending_restarts.borrow_mut().remove(&shard_id);
+                    self.requeue_for_restart(shard_id);
+                }
+            },
+            None => self.requeue_for_restart(shard_id),
+        }
+    }
+
+    fn requeue_for_restart(&self, shard_id: u64) {
+        if let Err(e) = self.queue_sender.try_send(shard_id) {
+            error!("could not requeue shard {} for restart: {:?}", shard_id, e);
+        }
+    }
+}
+
+/// Minimum spacing, per `max_concurrency` identify bucket, enforced by
+/// Discord between IDENTIFYs that share a bucket key.
+const IDENTIFY_BUCKET_WINDOW: Duration = Duration::from_secs(5);
+
+/// Advances the start queue on `Ready` and, per Discord's Invalid Session
+/// protocol, only drops the stored resume session on `InvalidateSession`
+/// when `resumable` is `false` — a resumable session should be kept so the
+/// next start can still RESUME instead of IDENTIFY. Shared by
+/// [`ShardManager::process`] and [`ShardEventStream`] so both entry points
+/// behave identically.
+fn advance_shard_queue(
+    queue: &StartQueue,
+    queue_sender: &MpscSender<u64>,
+    reconnect_queue: &SimpleReconnectQueue,
+    shard_id: ShardId,
+    event: &GatewayEvent,
+) {
+    match event {
+        GatewayEvent::Dispatch(_, Event::Ready(event)) => {
+            let ready_shard_id = match &event.ready.shard {
                 Some(shard) => shard[0],
                 None => {
                     error!("ready event has no shard id");
@@ -157,12 +405,30 @@ impl ShardManager {
                 }
             };
 
-            println!("shard id {} has started", &shard_id);
+            println!("shard id {} has started", &ready_shard_id);
 
-            if let Err(e) = self.queue_sender.try_send(shard_id) {
-                error!("could not send shard id to queue mpsc receiver: {:?}", e);
+            if let Some(next_shard_id) = queue.borrow_mut().pop_front() {
+                if let Err(e) = queue_sender.try_send(next_shard_id) {
+                    error!("could not send shard id to queue mpsc receiver: {:?}", e);
+                }
             }
-        }
+        },
+        GatewayEvent::InvalidateSession(resumable) => {
+            if *resumable {
+                trace!(
+                    "shard {} session invalidated but marked resumable; keeping stored session",
+                    shard_id,
+                );
+            } else {
+                trace!(
+                    "shard {} session invalidated and not resumable; dropping stored session",
+                    shard_id,
+                );
+
+                reconnect_queue.invalidate(shard_id);
+            }
+        },
+        _ => {},
     }
 }
 
@@ -170,50 +436,139 @@ fn process_queue(
     queue_receiver: MpscReceiver<u64>,
     token: Rc<String>,
     shards_total: u64,
+    max_concurrency: u64,
+    config_fn: ConfigFn,
     handle: Handle,
     sender: UnboundedSender<Message>,
     shards_map: ShardsMap,
+    controls: ControlsMap,
+    reconnect_queue: SimpleReconnectQueue,
+    queue_sender: MpscSender<u64>,
+    pending_restarts: PendingRestarts,
 ) -> impl Future<Item = (), Error = ()> {
     let timer = Timer::default();
+    let max_concurrency = max_concurrency.max(1);
+    // Tracks the instant each identify bucket (`shard_id % max_concurrency`)
+    // was last released, so shards sharing a bucket stay 5 seconds apart
+    // while shards in different buckets can start in the same window.
+    let last_start: Rc<RefCell<HashMap<u64, Instant>>> = Rc::new(RefCell::new(HashMap::new()));
 
     queue_receiver
-        .map(move |shard_id| {
+        .for_each(move |shard_id| {
             trace!("received message to start shard {}", &shard_id);
             let token = token.clone();
+            let config_fn = config_fn.clone();
             let handle = handle.clone();
+            let spawn_handle = handle.clone();
             let sender = sender.clone();
             let shards_map = shards_map.clone();
-            let sleep_future = timer.sleep(Duration::from_secs(6));
+            let controls = controls.clone();
+            let reconnect_queue = reconnect_queue.clone();
+            let queue_sender = queue_sender.clone();
+            let pending_restarts = pending_restarts.clone();
+
+            let resume = reconnect_queue.take(shard_id);
+
+            // A resumable session skips the bucketed identify delay
+            // entirely, since RESUME doesn't consume identify budget.
+            let sleep_future = if resume.is_some() {
+                timer.sleep(Duration::from_secs(0))
+            } else {
+                let bucket = shard_id % max_concurrency;
+                let now = Instant::now();
+                let start_at = next_bucket_start(bucket, now, &mut last_start.borrow_mut());
+
+                timer.sleep(start_at.duration_since(now))
+            };
 
-            sleep_future
+            // Spawned rather than awaited here, so the queue keeps taking ids
+            // while a shard is still sleeping or connecting, and ids in
+            // different identify buckets that arrive together can start
+            // concurrently. During `start` the next id is only sent once the
+            // previous shard is `Ready`, so initial startup still brings
+            // shards up one at a time.
+            let task = sleep_future
                 .map_err(|e| error!("Error sleeping before starting next shard: {:?}", e))
                 .and_then(move |_| {
-                    start_shard(token, shard_id, shards_total, handle.clone(), sender)
-                        .map(move |shard| {
+                    start_shard(
+                        token,
+                        shard_id,
+                        shards_total,
+                        config_fn,
+                        resume,
+                        handle.clone(),
+                        sender,
+                        shards_map.clone(),
+                        controls,
+                        reconnect_queue,
+                        queue_sender,
+                        pending_restarts,
+                    ).map(move |shard| {
                             shards_map.borrow_mut().insert(shard_id.clone(), shard);
-                        }) 
+                        })
+                });
 
-                    /*let future = start_shard(token, shard_id, shards_total, handle.clone(), sender)
-                        .map(move |shard| {
-                            shards_map.borrow_mut().insert(shard_id.clone(), shard);
-                        });
+            spawn_handle.spawn(task);
 
-                    handle.spawn(future);*/
-                })
+            future::ok(())
         })
-        .into_future()
-        .map(|_| ())
-        .map_err(|_| ())
+}
+
+/// Computes the instant a shard in `bucket` may send its IDENTIFY, given the
+/// last instant recorded for that bucket (if any), and records the result
+/// back into `last_start` for the next caller in the same bucket. Pulled out
+/// of [`process_queue`] so the bucketing rule is testable on its own.
+fn next_bucket_start(
+    bucket: u64,
+    now: Instant,
+    last_start: &mut HashMap<u64, Instant>,
+) -> Instant {
+    let earliest = last_start.get(&bucket)
+        .map(|&last| last + IDENTIFY_BUCKET_WINDOW)
+        .unwrap_or(now);
+    let start_at = ::std::cmp::max(now, earliest);
+    last_start.insert(bucket, start_at);
+
+    start_at
 }
 
 fn start_shard(
-    token: Rc<String>, 
-    shard_id: u64, 
-    shards_total: u64, 
-    handle: Handle, 
+    token: Rc<String>,
+    shard_id: u64,
+    shards_total: u64,
+    config_fn: ConfigFn,
+    resume: Option<ResumeSession>,
+    handle: Handle,
     sender: UnboundedSender<Message>,
+    shards_map: ShardsMap,
+    controls: ControlsMap,
+    reconnect_queue: SimpleReconnectQueue,
+    queue_sender: MpscSender<u64>,
+    pending_restarts: PendingRestarts,
 ) -> impl Future<Item = WrappedShard, Error = ()> {
-    Shard::new(token, [shard_id, shards_total], handle.clone())
+    let config = config_fn(shard_id, ShardConfigBuilder::new());
+
+    let connect = match resume {
+        Some(session) => {
+            trace!(
+                "resuming shard {} session {} at seq {}",
+                shard_id, session.session_id, session.seq,
+            );
+
+            Either::A(Shard::resume(
+                token,
+                [shard_id, shards_total],
+                config,
+                session.resume_gateway_url,
+                session.session_id,
+                session.seq,
+                handle.clone(),
+            ))
+        },
+        None => Either::B(Shard::new(token, [shard_id, shards_total], config, handle.clone())),
+    };
+
+    connect
         .then(move |result| {
             let shard = match result {
                 Ok(shard) => Rc::new(RefCell::new(shard)),
@@ -223,16 +578,67 @@ fn start_shard(
              };
 
             let sink = MessageSink {
-                shard: shard.clone(), 
+                shard: shard.clone(),
                 sender,
             };
 
-            let future = Box::new(shard.borrow_mut()
+            let (stop_tx, stop_rx) = oneshot::channel();
+            controls.borrow_mut().insert(shard_id, stop_tx);
+
+            let forward = shard.borrow_mut()
                 .messages()
                 .map_err(MessageSinkError::from)
                 .forward(sink)
                 .map(|_| ())
-                .map_err(|e| error!("Error forwarding shard messages to sink: {:?}", e)));
+                .map_err(move |e| error!("Error forwarding shard messages to sink: {:?}", e));
+
+            let shard_to_close = shard.clone();
+            let stop = stop_rx
+                .map_err(|_| ())
+                .map(move |_| {
+                    trace!("closing shard {} websocket after stop signal", shard_id);
+
+                    if let Err(e) = shard_to_close.borrow_mut().close() {
+                        error!("Error closing shard {} websocket: {:?}", shard_id, e);
+                    }
+                });
+
+            let shard_for_session = shard.clone();
+
+            let future = Box::new(forward.select(stop)
+                .map(|_| ())
+                .map_err(|_| ())
+                .then(move |result| {
+                    // Stash whatever session the shard still has so a
+                    // future restart can RESUME instead of IDENTIFY.
+                    let shard_ref = shard_for_session.borrow();
+                    if let (Some(session_id), Some(resume_gateway_url)) =
+                        (shard_ref.session_id(), shard_ref.resume_gateway_url())
+                    {
+                        reconnect_queue.store(shard_id, ResumeSession {
+                            session_id: session_id.to_owned(),
+                            seq: shard_ref.seq(),
+                            resume_gateway_url: resume_gateway_url.to_owned(),
+                        });
+                    }
+                    drop(shard_ref);
+
+                    shards_map.borrow_mut().remove(&shard_id);
+                    controls.borrow_mut().remove(&shard_id);
+
+                    // Only now has the old websocket actually finished
+                    // closing, so it's safe to let a pending restart start
+                    // the new connection for this shard id.
+                    if pending_restarts.borrow_mut().remove(&shard_id) {
+                        trace!("shard {} finished closing for restart; requeuing", shard_id);
+
+                        if let Err(e) = queue_sender.try_send(shard_id) {
+                            error!("could not requeue shard {} for restart: {:?}", shard_id, e);
+                        }
+                    }
+
+                    result
+                }));
 
             handle.spawn(future);
             future::ok(shard)
@@ -288,4 +694,243 @@ impl Sink for MessageSink {
         self.sender.poll_complete()
             .map_err(From::from)
     }
+}
+
+type ShardEventFuture = Box<Future<Item = (WrappedShard, GatewayEvent), Error = Error>>;
+type PendingEvents = Rc<RefCell<FuturesUnordered<ShardEventFuture>>>;
+
+fn next_shard_event(
+    receiver: Rc<RefCell<MessageStream>>,
+    queue: StartQueue,
+    queue_sender: MpscSender<u64>,
+    reconnect_queue: SimpleReconnectQueue,
+) -> ShardEventFuture {
+    Box::new(
+        future::poll_fn(move || receiver.borrow_mut().poll())
+            .map_err(|_| Error::Other("shard message stream errored"))
+            .and_then(move |message| match message {
+                Some((shard, message)) => {
+                    let event = {
+                        let mut shard_ref = shard.borrow_mut();
+                        let event = shard_ref.parse(message).map_err(Error::from)?;
+                        shard_ref.process(&event);
+                        event
+                    };
+
+                    let shard_id = shard.borrow().id();
+                    advance_shard_queue(&queue, &queue_sender, &reconnect_queue, shard_id, &event);
+
+                    Ok((shard, event))
+                },
+                None => Err(Error::Other("shard message stream ended")),
+            })
+    )
+}
+
+/// A higher-level combinator over [`ShardManager::messages`] that yields each
+/// decoded, already-processed [`GatewayEvent`] together with a
+/// [`ShardEventGuard`] granting access to the shard it came from. Internally
+/// there is only ever one outstanding poll against the already-merged
+/// `messages()` channel at a time; the guard re-queues that single poll for
+/// the next event when dropped, so a consumer can `for_each` over events
+/// without ever touching the raw message channel.
+pub struct ShardEventStream {
+    receiver: Rc<RefCell<MessageStream>>,
+    pending: PendingEvents,
+    queue: StartQueue,
+    queue_sender: MpscSender<u64>,
+    reconnect_queue: SimpleReconnectQueue,
+}
+
+impl ShardEventStream {
+    pub fn new(
+        receiver: MessageStream,
+        queue: StartQueue,
+        queue_sender: MpscSender<u64>,
+        reconnect_queue: SimpleReconnectQueue,
+    ) -> Self {
+        let receiver = Rc::new(RefCell::new(receiver));
+        let pending = Rc::new(RefCell::new(FuturesUnordered::new()));
+        pending.borrow_mut().push(next_shard_event(
+            receiver.clone(),
+            queue.clone(),
+            queue_sender.clone(),
+            reconnect_queue.clone(),
+        ));
+
+        Self { receiver, pending, queue, queue_sender, reconnect_queue }
+    }
+}
+
+impl Stream for ShardEventStream {
+    type Item = ShardEventGuard;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match try_ready!(self.pending.borrow_mut().poll()) {
+            Some((shard, event)) => Ok(Async::Ready(Some(ShardEventGuard {
+                shard,
+                event,
+                receiver: self.receiver.clone(),
+                pending: self.pending.clone(),
+                queue: self.queue.clone(),
+                queue_sender: self.queue_sender.clone(),
+                reconnect_queue: self.reconnect_queue.clone(),
+            }))),
+            None => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+/// Yielded by [`ShardEventStream`] for every decoded event. Grants mutable
+/// access to the shard the event came from via [`ShardEventGuard::shard`];
+/// dropping the guard queues the next poll of the merged message channel,
+/// which yields the next message from whichever shard sends one.
+pub struct ShardEventGuard {
+    shard: WrappedShard,
+    event: GatewayEvent,
+    receiver: Rc<RefCell<MessageStream>>,
+    pending: PendingEvents,
+    queue: StartQueue,
+    queue_sender: MpscSender<u64>,
+    reconnect_queue: SimpleReconnectQueue,
+}
+
+impl ShardEventGuard {
+    pub fn shard(&self) -> RefMut<Shard> {
+        self.shard.borrow_mut()
+    }
+
+    pub fn event(&self) -> &GatewayEvent {
+        &self.event
+    }
+}
+
+impl Drop for ShardEventGuard {
+    fn drop(&mut self) {
+        self.pending.borrow_mut().push(next_shard_event(
+            self.receiver.clone(),
+            self.queue.clone(),
+            self.queue_sender.clone(),
+            self.reconnect_queue.clone(),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_start_staggers_same_bucket_by_window() {
+        let mut last_start = HashMap::new();
+        let t0 = Instant::now();
+
+        let first = next_bucket_start(0, t0, &mut last_start);
+        assert_eq!(first, t0);
+
+        let second = next_bucket_start(0, t0, &mut last_start);
+        assert_eq!(second, t0 + IDENTIFY_BUCKET_WINDOW);
+    }
+
+    #[test]
+    fn bucket_start_is_independent_per_bucket() {
+        let mut last_start = HashMap::new();
+        let t0 = Instant::now();
+
+        next_bucket_start(0, t0, &mut last_start);
+        let other_bucket_start = next_bucket_start(1, t0, &mut last_start);
+
+        assert_eq!(other_bucket_start, t0);
+    }
+
+    fn test_session() -> ResumeSession {
+        ResumeSession {
+            session_id: "test-session".to_owned(),
+            seq: 42,
+            resume_gateway_url: "wss://example.invalid".to_owned(),
+        }
+    }
+
+    #[test]
+    fn reconnect_queue_take_removes_the_stored_session() {
+        let queue = SimpleReconnectQueue::new(1);
+        queue.store(0, test_session());
+
+        assert!(queue.take(0).is_some());
+        assert!(queue.take(0).is_none());
+    }
+
+    #[test]
+    fn reconnect_queue_invalidate_drops_the_stored_session() {
+        let queue = SimpleReconnectQueue::new(1);
+        queue.store(0, test_session());
+
+        queue.invalidate(0);
+
+        assert!(queue.take(0).is_none());
+    }
+
+    #[test]
+    fn reconnect_queue_take_is_per_shard() {
+        let queue = SimpleReconnectQueue::new(2);
+        queue.store(0, test_session());
+
+        assert!(queue.take(1).is_none());
+        assert!(queue.take(0).is_some());
+    }
+
+    #[test]
+    fn reconnect_queue_rejects_a_store_after_invalidate_until_the_next_take() {
+        let queue = SimpleReconnectQueue::new(1);
+        queue.invalidate(0);
+
+        // A disconnect that was already in flight when the invalidate
+        // landed must not resurrect the session it was told to drop.
+        queue.store(0, test_session());
+        assert!(queue.take(0).is_none());
+
+        // Once a new connection attempt has taken over (even finding
+        // nothing to resume), storing again is allowed.
+        queue.store(0, test_session());
+        assert!(queue.take(0).is_some());
+    }
+
+    fn no_queue_sender() -> MpscSender<u64> {
+        channel(1).0
+    }
+
+    #[test]
+    fn advance_shard_queue_keeps_the_session_on_a_resumable_invalidate() {
+        let queue: StartQueue = Rc::new(RefCell::new(VecDeque::new()));
+        let reconnect_queue = SimpleReconnectQueue::new(1);
+        reconnect_queue.store(0, test_session());
+
+        advance_shard_queue(
+            &queue,
+            &no_queue_sender(),
+            &reconnect_queue,
+            0,
+            &GatewayEvent::InvalidateSession(true),
+        );
+
+        assert!(reconnect_queue.take(0).is_some());
+    }
+
+    #[test]
+    fn advance_shard_queue_drops_the_session_on_a_non_resumable_invalidate() {
+        let queue: StartQueue = Rc::new(RefCell::new(VecDeque::new()));
+        let reconnect_queue = SimpleReconnectQueue::new(1);
+        reconnect_queue.store(0, test_session());
+
+        advance_shard_queue(
+            &queue,
+            &no_queue_sender(),
+            &reconnect_queue,
+            0,
+            &GatewayEvent::InvalidateSession(false),
+        );
+
+        assert!(reconnect_queue.take(0).is_none());
+    }
 }
\ No newline at end of file